@@ -1,8 +1,10 @@
 use clap::Parser;
 use glob::glob;
-use image::{GenericImageView, Rgba, RgbaImage};
+use image::{Rgba, RgbaImage};
 use rayon::prelude::*;
+use rustfft::{num_complex::Complex, FftPlanner};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -16,13 +18,116 @@ struct Args {
     #[arg(short, long, required = true, num_args = 1.., value_delimiter = ' ')]
     overlays: Vec<String>,
 
-    /// Treat white as transparent
-    #[arg(short, long)]
-    white_transparent: bool,
-
     /// Output format (text or json)
     #[arg(long = "print-format", value_name = "FORMAT", default_value = "text")]
     print_format: String,
+
+    /// Maximum color distance for a pixel to still count as a match (0 = exact; squared for `rgb`)
+    #[arg(long, default_value_t = 0.0)]
+    tolerance: f64,
+
+    /// Color distance metric used when `tolerance` is greater than zero
+    #[arg(long, value_enum, default_value_t = ColorMetric::Rgb)]
+    color_metric: ColorMetric,
+
+    /// Matching algorithm: brute-force per-pixel, or FFT-based normalized cross-correlation
+    #[arg(long, value_enum, default_value_t = MatchMode::Brute)]
+    mode: MatchMode,
+
+    /// Hex color (e.g. `ffffff` or `#00ff00`) to key out of overlays as transparent
+    #[arg(long, value_parser = parse_hex_color)]
+    transparent_color: Option<[u8; 3]>,
+
+    /// Color distance tolerance for `--transparent-color` (0 = exact hex match)
+    #[arg(long, default_value_t = 0.0)]
+    chroma_tolerance: f64,
+
+    /// Write an annotated copy of the background (match rectangles + overlay composite)
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Opacity (0.0-1.0) used when compositing the overlay onto its best match
+    #[arg(long, default_value_t = 0.5)]
+    overlay_opacity: f64,
+
+    /// JSONPath expression to select from the output, e.g. `$.overlays[*].matches[0]`
+    #[arg(long)]
+    query: Option<String>,
+}
+
+/// Per-pixel weight (0.0 masked, 1.0 unmasked) for each overlay pixel under `options`.
+fn overlay_mask(overlay: &RgbaImage, options: &MatchOptions) -> Vec<f64> {
+    overlay
+        .pixels()
+        .map(|p| if options.is_masked(*p) { 0.0 } else { 1.0 })
+        .collect()
+}
+
+/// Parses a hex color like `ffffff` or `#ffffff` into its RGB components.
+fn parse_hex_color(s: &str) -> Result<[u8; 3], String> {
+    let s = s.trim_start_matches('#');
+    if s.len() != 6 {
+        return Err(format!("expected a 6-digit hex color, got `{s}`"));
+    }
+    let channel = |slice: &str| {
+        u8::from_str_radix(slice, 16).map_err(|e| format!("invalid hex color `{s}`: {e}"))
+    };
+    Ok([channel(&s[0..2])?, channel(&s[2..4])?, channel(&s[4..6])?])
+}
+
+/// Tolerance and transparency configuration threaded through the matching functions.
+#[derive(Clone, Copy, Debug)]
+struct MatchOptions {
+    tolerance: f64,
+    color_metric: ColorMetric,
+    transparent_color: Option<[u8; 3]>,
+    chroma_tolerance: f64,
+}
+
+impl MatchOptions {
+    /// True if `ov_pixel` is transparent (alpha 0) or keys out against `transparent_color`.
+    fn is_masked(&self, ov_pixel: Rgba<u8>) -> bool {
+        if ov_pixel[3] == 0 {
+            return true;
+        }
+        let Some([r, g, b]) = self.transparent_color else {
+            return false;
+        };
+        let key = Rgba([r, g, b, 255]);
+        if self.chroma_tolerance > 0.0 {
+            color_distance(ov_pixel, key, self.color_metric) <= self.chroma_tolerance
+        } else {
+            ov_pixel[0] == r && ov_pixel[1] == g && ov_pixel[2] == b
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum MatchMode {
+    /// O(W*H*w*h) exact/tolerance-based per-pixel comparison
+    Brute,
+    /// O(N log N) normalized cross-correlation via FFT, for large backgrounds
+    Ncc,
+}
+
+/// `--tolerance`/`--color-metric` are per-pixel brute-force options; NCC produces
+/// a continuous correlation coefficient instead, so reject the combination.
+fn validate_mode_args(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    if args.mode == MatchMode::Ncc && (args.tolerance != 0.0 || args.color_metric != ColorMetric::Rgb)
+    {
+        return Err("--tolerance/--color-metric only apply to `--mode brute`".into());
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum ColorMetric {
+    /// Squared Euclidean distance in 8-bit sRGB space
+    Rgb,
+    /// Euclidean distance after mapping each channel through the sRGB->linear transform
+    Linear,
+    /// CIE76 ΔE distance in CIELAB space
+    Cie76,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -32,6 +137,8 @@ struct MatchResult {
     match_score: f64,
     is_perfect: bool,
     is_border_match: bool,
+    /// Overlay pixels that survived masking and contributed to `match_score`.
+    contributing_pixels: u32,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -39,6 +146,9 @@ struct ImageInfo {
     filename: String,
     width: u32,
     height: u32,
+    /// Packed blurhash fingerprint of the whole, unmasked image. Candidate pruning in
+    /// `find_best_matches` compares raw masked factors, not this string.
+    blurhash: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -52,7 +162,7 @@ struct JsonOutput {
     ekman_version: String,
     background: ImageInfo,
     overlays: Vec<OverlayResult>,
-    white_transparent: bool,
+    transparent_color: Option<String>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -62,26 +172,49 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let background = image::open(&args.background)?.to_rgba8();
     let bg_dimensions = background.dimensions();
 
+    let match_options = MatchOptions {
+        tolerance: args.tolerance,
+        color_metric: args.color_metric,
+        transparent_color: args.transparent_color,
+        chroma_tolerance: args.chroma_tolerance,
+    };
+
+    validate_mode_args(&args)?;
+
     let overlay_paths = expand_glob_patterns(&args.overlays)?;
     let mut all_results = Vec::new();
 
     for overlay_path in overlay_paths {
-        let results = process_overlay(&background, &overlay_path, args.white_transparent)?;
-        if !print_json {
+        let results = process_overlay(&background, &overlay_path, &match_options, args.mode)?;
+        if !print_json && args.query.is_none() {
             println!("\nOverlay: {}", overlay_path.display());
             print_report(&results);
         }
         all_results.push((overlay_path, results));
     }
 
-    if print_json {
+    if print_json || args.query.is_some() {
+        let background_blurhash =
+            encode_blurhash(&background, BLURHASH_COMPONENTS_X, BLURHASH_COMPONENTS_Y);
         let json_output = generate_json_output(
             &args.background,
+            &background_blurhash,
             &all_results,
-            args.white_transparent,
+            args.transparent_color,
             bg_dimensions,
         );
-        println!("{}", serde_json::to_string_pretty(&json_output)?);
+
+        if let Some(query) = &args.query {
+            let selected = query_json_output(&json_output, query)?;
+            println!("{}", serde_json::to_string_pretty(&selected)?);
+        } else {
+            println!("{}", serde_json::to_string_pretty(&json_output)?);
+        }
+    }
+
+    if let Some(output_path) = &args.output {
+        let annotated = render_annotated_output(&background, &all_results, args.overlay_opacity)?;
+        annotated.save(output_path)?;
     }
 
     Ok(())
@@ -106,33 +239,358 @@ fn expand_glob_patterns(patterns: &[String]) -> Result<Vec<PathBuf>, Box<dyn std
 fn process_overlay(
     background: &RgbaImage,
     overlay_path: &PathBuf,
-    treat_white_as_transparent: bool,
+    options: &MatchOptions,
+    mode: MatchMode,
 ) -> Result<Vec<MatchResult>, Box<dyn std::error::Error>> {
     let overlay = image::open(overlay_path)?.to_rgba8();
-    let results = find_best_matches(background, &overlay, treat_white_as_transparent);
+    let results = match mode {
+        MatchMode::Brute => find_best_matches(background, &overlay, options),
+        MatchMode::Ncc => find_best_matches_ncc(background, &overlay, options),
+    };
     Ok(results)
 }
 
 fn find_best_matches(
     background: &RgbaImage,
     overlay: &RgbaImage,
-    treat_white_as_transparent: bool,
+    options: &MatchOptions,
+) -> Vec<MatchResult> {
+    let (bg_width, bg_height) = background.dimensions();
+    let (ov_width, ov_height) = overlay.dimensions();
+
+    let mask = overlay_mask(overlay, options);
+    let overlay_factors = compute_blurhash_factors(
+        overlay,
+        BLURHASH_COMPONENTS_X,
+        BLURHASH_COMPONENTS_Y,
+        Some(&mask),
+    );
+    let mut positions = prefilter_candidate_positions(
+        background,
+        &overlay_factors,
+        &mask,
+        ov_width,
+        ov_height,
+        bg_width,
+        bg_height,
+    );
+    if positions.is_empty() {
+        // The coarse prefilter found nothing within the cutoff; fall back to an
+        // exhaustive scan rather than silently reporting no matches.
+        positions = (0..=bg_width - ov_width)
+            .flat_map(|x| (0..=bg_height - ov_height).map(move |y| (x, y)))
+            .collect();
+    }
+
+    let mut results: Vec<MatchResult> = positions
+        .par_iter()
+        .map(|&(x, y)| {
+            let (match_score, contributing_pixels) =
+                calculate_match_score(background, overlay, x, y, options);
+            // `is_perfect` stays reserved for bit-exact hits so existing JSON
+            // consumers keep seeing the same meaning even when tolerance > 0.
+            let is_perfect = options.tolerance == 0.0 && match_score == 1.0;
+            let is_border_match = check_border_match(background, overlay, x, y, options);
+
+            MatchResult {
+                x,
+                y,
+                match_score,
+                is_perfect,
+                is_border_match,
+                contributing_pixels,
+            }
+        })
+        .collect();
+
+    results.sort_by(|a, b| b.match_score.partial_cmp(&a.match_score).unwrap());
+
+    if results.is_empty() || results[0].match_score <= 0.5 {
+        vec![results[0].clone()]
+    } else {
+        results
+            .into_iter()
+            .filter(|r| r.match_score > 0.5)
+            .collect()
+    }
+}
+
+/// Coefficient-space distance below which a blurhash prefilter candidate is worth scoring exactly.
+const BLURHASH_PREFILTER_CUTOFF: f64 = 0.25;
+
+/// Cosine bases and linear-light background pixels, precomputed once and reused
+/// across every candidate window in `prefilter_candidate_positions`.
+struct PrefilterTables {
+    /// `cos_x[i][dx] = cos(pi * i * dx / window_width)`
+    cos_x: Vec<Vec<f64>>,
+    /// `cos_y[j][dy] = cos(pi * j * dy / window_height)`
+    cos_y: Vec<Vec<f64>>,
+    /// Background pixels mapped to linear light, row-major, `bg_width * bg_height`.
+    linear_background: Vec<[f64; 3]>,
+    /// Per-pixel overlay mask (0.0/1.0, row-major), applied to every background window too.
+    mask: Vec<f64>,
+    bg_width: u32,
+    window_width: u32,
+    window_height: u32,
+    components_x: u32,
+    components_y: u32,
+}
+
+impl PrefilterTables {
+    fn new(
+        background: &RgbaImage,
+        mask: &[f64],
+        window_width: u32,
+        window_height: u32,
+        components_x: u32,
+        components_y: u32,
+    ) -> Self {
+        let cos_table = |components: u32, size: u32| -> Vec<Vec<f64>> {
+            (0..components)
+                .map(|c| {
+                    (0..size)
+                        .map(|d| (std::f64::consts::PI * c as f64 * d as f64 / size as f64).cos())
+                        .collect()
+                })
+                .collect()
+        };
+
+        let linear_background = background
+            .pixels()
+            .map(|p| {
+                [
+                    srgb_to_linear(p[0]),
+                    srgb_to_linear(p[1]),
+                    srgb_to_linear(p[2]),
+                ]
+            })
+            .collect();
+
+        Self {
+            cos_x: cos_table(components_x, window_width),
+            cos_y: cos_table(components_y, window_height),
+            linear_background,
+            mask: mask.to_vec(),
+            bg_width: background.width(),
+            window_width,
+            window_height,
+            components_x,
+            components_y,
+        }
+    }
+
+    /// Masked blurhash-style factor vector for the window at `(x, y)`.
+    fn window_factors(&self, x: u32, y: u32) -> Vec<[f64; 3]> {
+        let mut sums = vec![[0.0_f64; 3]; (self.components_x * self.components_y) as usize];
+
+        for dy in 0..self.window_height {
+            let row_start = (y + dy) as usize * self.bg_width as usize;
+            for dx in 0..self.window_width {
+                let weight = self.mask[(dy * self.window_width + dx) as usize];
+                let linear = self.linear_background[row_start + (x + dx) as usize];
+                for (j, cos_y) in self.cos_y.iter().enumerate() {
+                    let cy = cos_y[dy as usize];
+                    for (i, cos_x) in self.cos_x.iter().enumerate() {
+                        let basis = cos_x[dx as usize] * cy * weight;
+                        let sum = &mut sums[j * self.components_x as usize + i];
+                        sum[0] += basis * linear[0];
+                        sum[1] += basis * linear[1];
+                        sum[2] += basis * linear[2];
+                    }
+                }
+            }
+        }
+
+        let area = self.window_width as f64 * self.window_height as f64;
+        for j in 0..self.components_y {
+            for i in 0..self.components_x {
+                let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+                let scale = normalisation / area;
+                let sum = &mut sums[(j * self.components_x + i) as usize];
+                sum[0] *= scale;
+                sum[1] *= scale;
+                sum[2] *= scale;
+            }
+        }
+
+        sums
+    }
+}
+
+/// Euclidean distance between two raw (unpacked) blurhash factor vectors.
+fn blurhash_factor_distance(a: &[[f64; 3]], b: &[[f64; 3]]) -> f64 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| (0..3).map(|c| (x[c] - y[c]).powi(2)).sum::<f64>())
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Slides a coarse grid of overlay-sized windows over `background`, keeping only
+/// the grid positions whose masked blurhash factor vector is close to
+/// `overlay_factors`, together with every position from there up to the next grid
+/// point in x and y.
+fn prefilter_candidate_positions(
+    background: &RgbaImage,
+    overlay_factors: &[[f64; 3]],
+    mask: &[f64],
+    ov_width: u32,
+    ov_height: u32,
+    bg_width: u32,
+    bg_height: u32,
+) -> Vec<(u32, u32)> {
+    let stride = (ov_width.min(ov_height) / 4).max(1);
+    let mut candidates = Vec::new();
+
+    let tables = PrefilterTables::new(
+        background,
+        mask,
+        ov_width,
+        ov_height,
+        BLURHASH_COMPONENTS_X,
+        BLURHASH_COMPONENTS_Y,
+    );
+
+    let mut y = 0;
+    loop {
+        let mut x = 0;
+        loop {
+            let window_factors = tables.window_factors(x, y);
+
+            if blurhash_factor_distance(&window_factors, overlay_factors) <= BLURHASH_PREFILTER_CUTOFF
+            {
+                let x_end = (x + stride).min(bg_width - ov_width);
+                let y_end = (y + stride).min(bg_height - ov_height);
+                for cy in y..=y_end {
+                    for cx in x..=x_end {
+                        candidates.push((cx, cy));
+                    }
+                }
+            }
+
+            if x >= bg_width - ov_width {
+                break;
+            }
+            x = (x + stride).min(bg_width - ov_width);
+        }
+
+        if y >= bg_height - ov_height {
+            break;
+        }
+        y = (y + stride).min(bg_height - ov_height);
+    }
+
+    candidates.sort_unstable();
+    candidates.dedup();
+    candidates
+}
+
+/// Locates `overlay` in `background` via per-channel FFT normalized cross-correlation,
+/// masking out transparent/chroma-keyed overlay pixels from the template and window stats.
+fn find_best_matches_ncc(
+    background: &RgbaImage,
+    overlay: &RgbaImage,
+    options: &MatchOptions,
 ) -> Vec<MatchResult> {
     let (bg_width, bg_height) = background.dimensions();
     let (ov_width, ov_height) = overlay.dimensions();
+    let (bg_w, bg_h) = (bg_width as usize, bg_height as usize);
+    let (ov_w, ov_h) = (ov_width as usize, ov_height as usize);
+
+    let mask = overlay_mask(overlay, options);
+    let contributing_pixels = mask.iter().filter(|&&m| m > 0.0).count() as u32;
+
+    let mut planner = FftPlanner::new();
+    let mut combined_ncc = vec![0.0_f64; bg_w * bg_h];
+
+    if contributing_pixels > 0 {
+        let template_pixel_count = contributing_pixels as f64;
+
+        for channel in 0..3 {
+            let bg_channel: Vec<f64> = background.pixels().map(|p| p[channel] as f64).collect();
+            let bg_channel_sq: Vec<f64> = bg_channel.iter().map(|v| v * v).collect();
+            // Masked entries are zeroed so they drop out of every sum below.
+            let ov_channel: Vec<f64> = overlay
+                .pixels()
+                .zip(&mask)
+                .map(|(p, &m)| p[channel] as f64 * m)
+                .collect();
+
+            let template_mean = ov_channel.iter().sum::<f64>() / template_pixel_count;
+            let template_var: f64 = ov_channel
+                .iter()
+                .zip(&mask)
+                .filter(|(_, &m)| m > 0.0)
+                .map(|(v, _)| (v - template_mean).powi(2))
+                .sum();
+
+            let raw_corr = fft_cross_correlation(
+                &mut planner,
+                &bg_channel,
+                bg_w,
+                bg_h,
+                &ov_channel,
+                ov_w,
+                ov_h,
+            );
+            let window_sum_masked =
+                fft_cross_correlation(&mut planner, &bg_channel, bg_w, bg_h, &mask, ov_w, ov_h);
+            let window_sum_sq_masked = fft_cross_correlation(
+                &mut planner,
+                &bg_channel_sq,
+                bg_w,
+                bg_h,
+                &mask,
+                ov_w,
+                ov_h,
+            );
+
+            for y in 0..=(bg_h - ov_h) {
+                for x in 0..=(bg_w - ov_w) {
+                    let idx = y * bg_w + x;
+                    let window_sum = window_sum_masked[idx];
+                    let window_sum_sq = window_sum_sq_masked[idx];
+                    let window_var =
+                        window_sum_sq - window_sum * window_sum / template_pixel_count;
+
+                    let numerator = raw_corr[idx] - template_mean * window_sum;
+                    let denominator = (window_var * template_var).sqrt();
+
+                    let ncc = if denominator > 1e-9 {
+                        numerator / denominator
+                    } else {
+                        0.0
+                    };
+                    combined_ncc[idx] += ncc;
+                }
+            }
+        }
+    }
 
     let positions: Vec<(u32, u32)> = (0..=bg_width - ov_width)
         .flat_map(|x| (0..=bg_height - ov_height).map(move |y| (x, y)))
         .collect();
 
+    // Border exact-match check stays bit-exact (NCC doesn't use `tolerance`/
+    // `color_metric`), but still honors the overlay's transparency/chroma mask.
+    let border_options = MatchOptions {
+        tolerance: 0.0,
+        color_metric: ColorMetric::Rgb,
+        transparent_color: options.transparent_color,
+        chroma_tolerance: options.chroma_tolerance,
+    };
+
     let mut results: Vec<MatchResult> = positions
         .par_iter()
         .map(|&(x, y)| {
-            let match_score =
-                calculate_match_score(background, overlay, x, y, treat_white_as_transparent);
+            let avg_ncc = if contributing_pixels > 0 {
+                combined_ncc[y as usize * bg_w + x as usize] / 3.0
+            } else {
+                0.0
+            };
+            let match_score = ((avg_ncc + 1.0) / 2.0).clamp(0.0, 1.0);
             let is_perfect = match_score == 1.0;
-            let is_border_match =
-                check_border_match(background, overlay, x, y, treat_white_as_transparent);
+            let is_border_match = check_border_match(background, overlay, x, y, &border_options);
 
             MatchResult {
                 x,
@@ -140,6 +598,7 @@ fn find_best_matches(
                 match_score,
                 is_perfect,
                 is_border_match,
+                contributing_pixels,
             }
         })
         .collect();
@@ -156,13 +615,95 @@ fn find_best_matches(
     }
 }
 
+/// Cross-correlates `background_channel` against `template_channel` via `IFFT(FFT(f) * conj(FFT(t)))`.
+fn fft_cross_correlation(
+    planner: &mut FftPlanner<f64>,
+    background_channel: &[f64],
+    bg_w: usize,
+    bg_h: usize,
+    template_channel: &[f64],
+    ov_w: usize,
+    ov_h: usize,
+) -> Vec<f64> {
+    let pad_w = (bg_w + ov_w - 1).next_power_of_two();
+    let pad_h = (bg_h + ov_h - 1).next_power_of_two();
+
+    let mut f_data = vec![Complex::new(0.0, 0.0); pad_w * pad_h];
+    for y in 0..bg_h {
+        for x in 0..bg_w {
+            f_data[y * pad_w + x] = Complex::new(background_channel[y * bg_w + x], 0.0);
+        }
+    }
+
+    let mut t_data = vec![Complex::new(0.0, 0.0); pad_w * pad_h];
+    for y in 0..ov_h {
+        for x in 0..ov_w {
+            t_data[y * pad_w + x] = Complex::new(template_channel[y * ov_w + x], 0.0);
+        }
+    }
+
+    fft_2d(planner, &mut f_data, pad_w, pad_h, false);
+    fft_2d(planner, &mut t_data, pad_w, pad_h, false);
+
+    for (f, t) in f_data.iter_mut().zip(t_data.iter()) {
+        *f *= t.conj();
+    }
+
+    fft_2d(planner, &mut f_data, pad_w, pad_h, true);
+
+    let norm = (pad_w * pad_h) as f64;
+    let mut result = vec![0.0; bg_w * bg_h];
+    for y in 0..bg_h {
+        for x in 0..bg_w {
+            result[y * bg_w + x] = f_data[y * pad_w + x].re / norm;
+        }
+    }
+    result
+}
+
+/// In-place row-then-column 2D FFT (or inverse); rustfft's inverse is unnormalized.
+fn fft_2d(
+    planner: &mut FftPlanner<f64>,
+    data: &mut [Complex<f64>],
+    width: usize,
+    height: usize,
+    inverse: bool,
+) {
+    let row_fft = if inverse {
+        planner.plan_fft_inverse(width)
+    } else {
+        planner.plan_fft_forward(width)
+    };
+    for row in data.chunks_mut(width) {
+        row_fft.process(row);
+    }
+
+    let col_fft = if inverse {
+        planner.plan_fft_inverse(height)
+    } else {
+        planner.plan_fft_forward(height)
+    };
+    let mut column = vec![Complex::new(0.0, 0.0); height];
+    for x in 0..width {
+        for y in 0..height {
+            column[y] = data[y * width + x];
+        }
+        col_fft.process(&mut column);
+        for y in 0..height {
+            data[y * width + x] = column[y];
+        }
+    }
+}
+
+/// Returns the match score (fraction of contributing pixels that matched) and the
+/// contributing-pixel count.
 fn calculate_match_score(
     background: &RgbaImage,
     overlay: &RgbaImage,
     x: u32,
     y: u32,
-    treat_white_as_transparent: bool,
-) -> f64 {
+    options: &MatchOptions,
+) -> (f64, u32) {
     let (ov_width, ov_height) = overlay.dimensions();
     let mut matching_pixels = 0;
     let mut total_pixels = 0;
@@ -172,32 +713,48 @@ fn calculate_match_score(
             let bg_pixel = background.get_pixel(x + ov_x, y + ov_y);
             let ov_pixel = overlay.get_pixel(ov_x, ov_y);
 
-            if treat_white_as_transparent
-                && ov_pixel[0] == 255
-                && ov_pixel[1] == 255
-                && ov_pixel[2] == 255
-            {
+            if options.is_masked(*ov_pixel) {
                 continue;
             }
 
             total_pixels += 1;
-            if bg_pixel == ov_pixel {
+            if pixel_distance_within_tolerance(
+                *bg_pixel,
+                *ov_pixel,
+                options.tolerance,
+                options.color_metric,
+            ) {
                 matching_pixels += 1;
             }
         }
     }
 
-    matching_pixels as f64 / total_pixels as f64
+    let score = if total_pixels == 0 {
+        0.0
+    } else {
+        matching_pixels as f64 / total_pixels as f64
+    };
+    (score, total_pixels)
 }
 
+/// Checks that the overlay's (unmasked) border pixels line up with the background at `(x, y)`.
 fn check_border_match(
     background: &RgbaImage,
     overlay: &RgbaImage,
     x: u32,
     y: u32,
-    treat_white_as_transparent: bool,
+    options: &MatchOptions,
 ) -> bool {
     let (ov_width, ov_height) = overlay.dimensions();
+    let mut compared = 0u32;
+
+    let mut check_pixel = |bg_pixel: Rgba<u8>, ov_pixel: Rgba<u8>| -> bool {
+        if options.is_masked(ov_pixel) {
+            return true;
+        }
+        compared += 1;
+        pixels_match(bg_pixel, ov_pixel, options)
+    };
 
     for ov_x in 0..ov_width {
         let top_bg = *background.get_pixel(x + ov_x, y);
@@ -205,9 +762,7 @@ fn check_border_match(
         let bottom_bg = *background.get_pixel(x + ov_x, y + ov_height - 1);
         let bottom_ov = *overlay.get_pixel(ov_x, ov_height - 1);
 
-        if !pixels_match(top_bg, top_ov, treat_white_as_transparent)
-            || !pixels_match(bottom_bg, bottom_ov, treat_white_as_transparent)
-        {
+        if !check_pixel(top_bg, top_ov) || !check_pixel(bottom_bg, bottom_ov) {
             return false;
         }
     }
@@ -218,22 +773,102 @@ fn check_border_match(
         let right_bg = *background.get_pixel(x + ov_width - 1, y + ov_y);
         let right_ov = *overlay.get_pixel(ov_width - 1, ov_y);
 
-        if !pixels_match(left_bg, left_ov, treat_white_as_transparent)
-            || !pixels_match(right_bg, right_ov, treat_white_as_transparent)
-        {
+        if !check_pixel(left_bg, left_ov) || !check_pixel(right_bg, right_ov) {
             return false;
         }
     }
 
-    true
+    compared > 0
 }
 
-fn pixels_match(bg_pixel: Rgba<u8>, ov_pixel: Rgba<u8>, treat_white_as_transparent: bool) -> bool {
-    if treat_white_as_transparent && ov_pixel[0] == 255 && ov_pixel[1] == 255 && ov_pixel[2] == 255
-    {
-        true
+fn pixels_match(bg_pixel: Rgba<u8>, ov_pixel: Rgba<u8>, options: &MatchOptions) -> bool {
+    pixel_distance_within_tolerance(bg_pixel, ov_pixel, options.tolerance, options.color_metric)
+}
+
+fn pixel_distance_within_tolerance(
+    bg_pixel: Rgba<u8>,
+    ov_pixel: Rgba<u8>,
+    tolerance: f64,
+    color_metric: ColorMetric,
+) -> bool {
+    if tolerance <= 0.0 {
+        return bg_pixel == ov_pixel;
+    }
+    color_distance(bg_pixel, ov_pixel, color_metric) <= tolerance
+}
+
+/// Squared Euclidean distance between two pixels in 8-bit sRGB space.
+fn rgb_distance_sq(a: Rgba<u8>, b: Rgba<u8>) -> f64 {
+    let dr = a[0] as f64 - b[0] as f64;
+    let dg = a[1] as f64 - b[1] as f64;
+    let db = a[2] as f64 - b[2] as f64;
+    dr * dr + dg * dg + db * db
+}
+
+/// Maps an 8-bit sRGB channel value into linear light, per the standard sRGB EOTF.
+fn srgb_to_linear(channel: u8) -> f64 {
+    let c = channel as f64 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
     } else {
-        bg_pixel == ov_pixel
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_distance_sq(a: Rgba<u8>, b: Rgba<u8>) -> f64 {
+    let dr = srgb_to_linear(a[0]) - srgb_to_linear(b[0]);
+    let dg = srgb_to_linear(a[1]) - srgb_to_linear(b[1]);
+    let db = srgb_to_linear(a[2]) - srgb_to_linear(b[2]);
+    dr * dr + dg * dg + db * db
+}
+
+/// Converts an sRGB pixel to CIELAB (D65 white point).
+fn rgb_to_lab(pixel: Rgba<u8>) -> (f64, f64, f64) {
+    let r = srgb_to_linear(pixel[0]);
+    let g = srgb_to_linear(pixel[1]);
+    let b = srgb_to_linear(pixel[2]);
+
+    let x = r * 0.4124564 + g * 0.3575761 + b * 0.1804375;
+    let y = r * 0.2126729 + g * 0.7151522 + b * 0.0721750;
+    let z = r * 0.0193339 + g * 0.1191920 + b * 0.9503041;
+
+    const XN: f64 = 0.95047;
+    const YN: f64 = 1.0;
+    const ZN: f64 = 1.08883;
+    const DELTA: f64 = 6.0 / 29.0;
+
+    fn f(t: f64) -> f64 {
+        if t > DELTA.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * DELTA.powi(2)) + 4.0 / 29.0
+        }
+    }
+
+    let (fx, fy, fz) = (f(x / XN), f(y / YN), f(z / ZN));
+
+    let l = 116.0 * fy - 16.0;
+    let a = 500.0 * (fx - fy);
+    let b = 200.0 * (fy - fz);
+
+    (l, a, b)
+}
+
+fn cie76_distance(a: Rgba<u8>, b: Rgba<u8>) -> f64 {
+    let (l1, a1, b1) = rgb_to_lab(a);
+    let (l2, a2, b2) = rgb_to_lab(b);
+    let dl = l1 - l2;
+    let da = a1 - a2;
+    let db = b1 - b2;
+    (dl * dl + da * da + db * db).sqrt()
+}
+
+/// Distance between two pixels under `metric`; `Rgb` is squared, `Linear`/`Cie76` are not.
+fn color_distance(a: Rgba<u8>, b: Rgba<u8>, metric: ColorMetric) -> f64 {
+    match metric {
+        ColorMetric::Rgb => rgb_distance_sq(a, b),
+        ColorMetric::Linear => linear_distance_sq(a, b).sqrt(),
+        ColorMetric::Cie76 => cie76_distance(a, b),
     }
 }
 
@@ -241,21 +876,23 @@ fn print_report(results: &[MatchResult]) {
     println!("Match Report:");
     for (index, result) in results.iter().enumerate() {
         println!(
-            "Match {}: Position: ({}, {}), Score: {:.2}, Perfect: {}, Border Match: {}",
+            "Match {}: Position: ({}, {}), Score: {:.2}, Perfect: {}, Border Match: {}, Contributing Pixels: {}",
             index + 1,
             result.x,
             result.y,
             result.match_score,
             result.is_perfect,
-            result.is_border_match
+            result.is_border_match,
+            result.contributing_pixels
         );
     }
 }
 
 fn generate_json_output(
     background: &PathBuf,
+    background_blurhash: &str,
     all_results: &[(PathBuf, Vec<MatchResult>)],
-    white_transparent: bool,
+    transparent_color: Option<[u8; 3]>,
     bg_dimensions: (u32, u32),
 ) -> JsonOutput {
     let background_info = ImageInfo {
@@ -267,18 +904,21 @@ fn generate_json_output(
             .to_string(),
         width: bg_dimensions.0,
         height: bg_dimensions.1,
+        blurhash: background_blurhash.to_string(),
     };
 
     let overlays = all_results
         .iter()
         .map(|(path, results)| {
-            let overlay = image::open(path).unwrap();
+            let overlay = image::open(path).unwrap().to_rgba8();
             let dimensions = overlay.dimensions();
+            let blurhash = encode_blurhash(&overlay, BLURHASH_COMPONENTS_X, BLURHASH_COMPONENTS_Y);
             OverlayResult {
                 image_info: ImageInfo {
                     filename: path.file_name().unwrap().to_str().unwrap().to_string(),
                     width: dimensions.0,
                     height: dimensions.1,
+                    blurhash,
                 },
                 matches: results.clone(),
             }
@@ -289,6 +929,220 @@ fn generate_json_output(
         ekman_version: env!("CARGO_PKG_VERSION").to_string(),
         background: background_info,
         overlays,
-        white_transparent,
+        transparent_color: transparent_color.map(|[r, g, b]| format!("#{r:02x}{g:02x}{b:02x}")),
+    }
+}
+
+/// Runs a JSONPath expression against the generated output, returning the selected nodes.
+fn query_json_output(
+    json_output: &JsonOutput,
+    query: &str,
+) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    let value = serde_json::to_value(json_output)?;
+    let selected = jsonpath_lib::select(&value, query)
+        .map_err(|e| format!("invalid JSONPath query `{query}`: {e}"))?;
+    Ok(selected.into_iter().cloned().collect())
+}
+
+const BLURHASH_COMPONENTS_X: u32 = 4;
+const BLURHASH_COMPONENTS_Y: u32 = 3;
+
+const BASE83_ALPHABET: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn encode_base83(mut value: u64, length: usize) -> String {
+    let mut digits = vec![0u8; length];
+    for digit in digits.iter_mut().rev() {
+        *digit = BASE83_ALPHABET[(value % 83) as usize];
+        value /= 83;
+    }
+    String::from_utf8(digits).unwrap()
+}
+
+fn linear_to_srgb(value: f64) -> u8 {
+    let v = value.clamp(0.0, 1.0);
+    let srgb = if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+fn sign_pow(value: f64, exponent: f64) -> f64 {
+    value.signum() * value.abs().powf(exponent)
+}
+
+/// Computes the raw (unpacked) `components_x * components_y` DCT-like basis
+/// coefficients for `image`; `(0, 0)` is the average color (DC), the rest are AC
+/// detail terms. An optional per-pixel `mask` (row-major) zero-weights pixels.
+fn compute_blurhash_factors(
+    image: &RgbaImage,
+    components_x: u32,
+    components_y: u32,
+    mask: Option<&[f64]>,
+) -> Vec<[f64; 3]> {
+    let (width, height) = image.dimensions();
+    let (width_f, height_f) = (width as f64, height as f64);
+
+    let mut factors = vec![[0.0_f64; 3]; (components_x * components_y) as usize];
+
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut sum = [0.0_f64; 3];
+
+            for (px, py, pixel) in image.enumerate_pixels() {
+                let weight = mask.map_or(1.0, |m| m[(py * width + px) as usize]);
+                let basis = (std::f64::consts::PI * i as f64 * px as f64 / width_f).cos()
+                    * (std::f64::consts::PI * j as f64 * py as f64 / height_f).cos()
+                    * weight;
+                sum[0] += basis * srgb_to_linear(pixel[0]);
+                sum[1] += basis * srgb_to_linear(pixel[1]);
+                sum[2] += basis * srgb_to_linear(pixel[2]);
+            }
+
+            let scale = normalisation / (width_f * height_f);
+            let idx = (j * components_x + i) as usize;
+            factors[idx] = [sum[0] * scale, sum[1] * scale, sum[2] * scale];
+        }
+    }
+
+    factors
+}
+
+/// Encodes `image` as a packed blurhash string (see [`compute_blurhash_factors`]/[`pack_blurhash`]).
+fn encode_blurhash(image: &RgbaImage, components_x: u32, components_y: u32) -> String {
+    let factors = compute_blurhash_factors(image, components_x, components_y, None);
+    pack_blurhash(&factors, components_x, components_y)
+}
+
+fn pack_blurhash(factors: &[[f64; 3]], components_x: u32, components_y: u32) -> String {
+    let dc = factors[0];
+    let ac = &factors[1..];
+
+    let max_ac = ac
+        .iter()
+        .flat_map(|channels| channels.iter())
+        .fold(0.0_f64, |acc, &v| acc.max(v.abs()));
+
+    let mut result = String::new();
+
+    let size_flag = (components_x - 1) + (components_y - 1) * 9;
+    result.push_str(&encode_base83(size_flag as u64, 1));
+
+    let quantized_max_ac = if max_ac > 0.0 {
+        ((max_ac * 166.0 - 0.5).floor() as i64).clamp(0, 82) as u64
+    } else {
+        0
+    };
+    result.push_str(&encode_base83(quantized_max_ac, 1));
+
+    let dc_value = ((linear_to_srgb(dc[0]) as u64) << 16)
+        | ((linear_to_srgb(dc[1]) as u64) << 8)
+        | (linear_to_srgb(dc[2]) as u64);
+    result.push_str(&encode_base83(dc_value, 4));
+
+    let actual_max_ac = (quantized_max_ac as f64 + 1.0) / 166.0;
+    for channels in ac {
+        let quantize = |v: f64| -> u64 {
+            let normalized = sign_pow(v / actual_max_ac, 0.5);
+            ((normalized * 9.0 + 9.5).floor() as i64).clamp(0, 18) as u64
+        };
+        let value = quantize(channels[0]) * 19 * 19 + quantize(channels[1]) * 19 + quantize(channels[2]);
+        result.push_str(&encode_base83(value, 2));
+    }
+
+    result
+}
+
+const MATCH_RECT_COLOR_PERFECT: Rgba<u8> = Rgba([0, 200, 0, 255]);
+const MATCH_RECT_COLOR_BORDER: Rgba<u8> = Rgba([0, 120, 255, 255]);
+const MATCH_RECT_COLOR_OTHER: Rgba<u8> = Rgba([255, 215, 0, 255]);
+
+/// Renders `background` with a rectangle around each match (green perfect, blue
+/// border, yellow other) and the best match's overlay composited in place.
+fn render_annotated_output(
+    background: &RgbaImage,
+    all_results: &[(PathBuf, Vec<MatchResult>)],
+    overlay_opacity: f64,
+) -> Result<RgbaImage, Box<dyn std::error::Error>> {
+    let mut canvas = background.clone();
+    let mut best: Option<(&PathBuf, &MatchResult)> = None;
+
+    for (path, results) in all_results {
+        let overlay = image::open(path)?.to_rgba8();
+        let (ov_width, ov_height) = overlay.dimensions();
+
+        for result in results {
+            let color = if result.is_perfect {
+                MATCH_RECT_COLOR_PERFECT
+            } else if result.is_border_match {
+                MATCH_RECT_COLOR_BORDER
+            } else {
+                MATCH_RECT_COLOR_OTHER
+            };
+            draw_rect_outline(&mut canvas, result.x, result.y, ov_width, ov_height, color);
+
+            if best.is_none_or(|(_, b)| result.match_score > b.match_score) {
+                best = Some((path, result));
+            }
+        }
+    }
+
+    if let Some((path, result)) = best {
+        let overlay = image::open(path)?.to_rgba8();
+        composite_overlay(&mut canvas, &overlay, result.x, result.y, overlay_opacity);
+    }
+
+    Ok(canvas)
+}
+
+/// Draws a one-pixel-wide `width x height` rectangle outline at `(x, y)`, clipped to the canvas.
+fn draw_rect_outline(canvas: &mut RgbaImage, x: u32, y: u32, width: u32, height: u32, color: Rgba<u8>) {
+    let (canvas_width, canvas_height) = canvas.dimensions();
+    let x_end = (x + width).min(canvas_width);
+    let y_end = (y + height).min(canvas_height);
+
+    for px in x..x_end {
+        canvas.put_pixel(px, y, color);
+        if y + height > 0 && y + height - 1 < canvas_height {
+            canvas.put_pixel(px, y + height - 1, color);
+        }
+    }
+    for py in y..y_end {
+        canvas.put_pixel(x, py, color);
+        if x + width > 0 && x + width - 1 < canvas_width {
+            canvas.put_pixel(x + width - 1, py, color);
+        }
+    }
+}
+
+/// Alpha-blends `overlay` onto `canvas` at `(x, y)`, scaling each pixel's alpha by `opacity`.
+fn composite_overlay(canvas: &mut RgbaImage, overlay: &RgbaImage, x: u32, y: u32, opacity: f64) {
+    let (ov_width, ov_height) = overlay.dimensions();
+    let opacity = opacity.clamp(0.0, 1.0);
+
+    for oy in 0..ov_height {
+        for ox in 0..ov_width {
+            let ov_pixel = overlay.get_pixel(ox, oy);
+            let bg_pixel = *canvas.get_pixel(x + ox, y + oy);
+            let alpha = (ov_pixel[3] as f64 / 255.0) * opacity;
+
+            let blend = |ov_channel: u8, bg_channel: u8| -> u8 {
+                (ov_channel as f64 * alpha + bg_channel as f64 * (1.0 - alpha)).round() as u8
+            };
+
+            canvas.put_pixel(
+                x + ox,
+                y + oy,
+                Rgba([
+                    blend(ov_pixel[0], bg_pixel[0]),
+                    blend(ov_pixel[1], bg_pixel[1]),
+                    blend(ov_pixel[2], bg_pixel[2]),
+                    255,
+                ]),
+            );
+        }
     }
 }